@@ -1,3 +1,5 @@
+use std::fmt;
+use std::io;
 use std::path::Path;
 
 mod elf;
@@ -38,37 +40,159 @@ pub enum Region {
 }
 
 impl Region {
-    fn from_str(s: &str) -> Region {
+    fn from_str(s: &str) -> Result<Region, Error> {
         match s {
-            "NA" => Region::NorthAmerica,
-            "E"  => Region::Europe,
-            "J"  => Region::Japan,
-            _    => panic!("Invalid region {}", s)
+            "NA" => Ok(Region::NorthAmerica),
+            "E"  => Ok(Region::Europe),
+            "J"  => Ok(Region::Japan),
+            _    => Err(Error::InvalidRegion(s.to_string())),
         }
     }
 }
 
+/// Everything that can go wrong converting an ELF into a PSX-EXE.
+/// Kept as a plain enum (rather than panicking) so the converter can
+/// be driven as a library and tested against malformed input.
+#[derive(Debug)]
+pub enum Error {
+    InvalidRegion(String),
+    InvalidOption(String),
+    MissingOptionValue(String),
+    BadMagic,
+    Not32Bit,
+    NotLittleEndian,
+    BadIdentVersion,
+    NotExecutable,
+    NotMips,
+    BadObjectVersion,
+    BadSectionHeaderSize,
+    BadProgramHeaderSize,
+    NoProgramHeaders,
+    BadSectionAlignment { addr: u32, align: u32 },
+    NoProgbits,
+    DiscontiguousMemfill,
+    ObjectTooBig(u32),
+    BadRelocationOffset,
+    InvalidNumber(String),
+    UnexpectedEof,
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidRegion(s) => write!(f, "Invalid region {}", s),
+            Error::InvalidOption(s) => write!(f, "Unknown option {}", s),
+            Error::MissingOptionValue(s) => write!(f, "{} requires a value argument", s),
+            Error::BadMagic => write!(f, "Invalid ELF file: bad magic"),
+            Error::Not32Bit => write!(f, "Invalid ELF file: not a 32bit object"),
+            Error::NotLittleEndian => write!(f, "Invalid ELF file: not a little endian object"),
+            Error::BadIdentVersion => write!(f, "Invalid ELF file: bad IDENT version"),
+            Error::NotExecutable => write!(f, "Invalid ELF file: not an executable"),
+            Error::NotMips => write!(f, "Invalid ELF file: not a MIPS executable"),
+            Error::BadObjectVersion => write!(f, "Invalid ELF file: bad object version"),
+            Error::BadSectionHeaderSize => write!(f, "Invalid ELF file: bad section header size"),
+            Error::BadProgramHeaderSize => write!(f, "Invalid ELF file: bad program header size"),
+            Error::NoProgramHeaders => write!(f, "ELF has no program headers to rebuild the image from"),
+            Error::BadSectionAlignment { addr, align } =>
+                write!(f, "bad section alignment: addr {:08x} align {}", addr, align),
+            Error::NoProgbits => write!(f, "No progbits section found"),
+            Error::DiscontiguousMemfill => write!(f, "Got discontiguous memfill sections"),
+            Error::ObjectTooBig(size) => write!(f, "Object is too big ({} bytes)", size),
+            Error::BadRelocationOffset => write!(f, "Relocation offset out of bounds"),
+            Error::InvalidNumber(s) => write!(f, "Invalid number {}", s),
+            Error::UnexpectedEof => write!(f, "Unexpected end of file"),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// Parse a CLI-supplied address/value, accepting an optional `0x`
+/// prefix for hex (the usual way these get written in linker scripts
+/// and map files) or a plain decimal number otherwise.
+fn parse_u32(s: &str) -> Result<u32, Error> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    let radix = if digits.len() != s.len() { 16 } else { 10 };
+    u32::from_str_radix(digits, radix).map_err(|_| Error::InvalidNumber(s.to_string()))
+}
+
 fn main() {
     let args: Vec<_> = std::env::args().collect();
 
     if args.len() < 4 {
-        println!("usage: elf2psexe <REGION> <elf-bin> <psx-bin>");
+        println!("usage: elf2psexe <REGION> <elf-bin> <psx-bin> [options]");
         println!("Valid regions: NA, E or J");
-        panic!("Missing argument");
+        println!("Options:");
+        println!("  --phdrs          Rebuild the image from PT_LOAD program headers");
+        println!("  --sections       Rebuild the image from ALLOC section headers");
+        println!("  --sym <path>     Write a no$psx-style symbol map to <path>");
+        println!("  --entry <addr>   Override the entry point");
+        println!("  --gp <addr>      Override the initial GP");
+        println!("  --sp <addr>      Override the initial SP");
+        println!("By default the image is rebuilt from program headers when the");
+        println!("ELF has any, otherwise from ALLOC section headers. Symbol table");
+        println!("entries named _gp/__gp, __stack/__sp/_stack_top and __entry");
+        println!("override the ELF's own GP, SP and entry point respectively; the");
+        println!("CLI flags above take precedence over all of those.");
+        std::process::exit(1);
+    }
+
+    if let Err(e) = run(&args) {
+        eprintln!("elf2psexe: {}", e);
+        std::process::exit(1);
     }
+}
 
-    let region = Region::from_str(&args[1]);
+fn run(args: &[String]) -> Result<(), Error> {
+    let region = Region::from_str(&args[1])?;
     let elfpath = &args[2];
     let psexepath = &args[3];
 
-    let elf = elf::ElfReader::new(Path::new(elfpath));
+    let mut layout = elf::Layout::Auto;
+    let mut sympath: Option<&str> = None;
+    let mut entry_override = None;
+    let mut gp_override = None;
+    let mut sp_override = None;
+
+    let mut rest = args[4..].iter();
+    while let Some(arg) = rest.next() {
+        let mut value_arg = |flag: &str| {
+            rest.next().ok_or_else(|| Error::MissingOptionValue(flag.to_string()))
+        };
+
+        match arg.as_str() {
+            "--phdrs" => layout = elf::Layout::Segments,
+            "--sections" => layout = elf::Layout::Sections,
+            "--sym" => sympath = Some(value_arg("--sym")?.as_str()),
+            "--entry" => entry_override = Some(parse_u32(value_arg("--entry")?)?),
+            "--gp" => gp_override = Some(parse_u32(value_arg("--gp")?)?),
+            "--sp" => sp_override = Some(parse_u32(value_arg("--sp")?)?),
+            flag => return Err(Error::InvalidOption(flag.to_string())),
+        }
+    }
+
+    let mut elf = elf::ElfReader::new(Path::new(elfpath), layout)?;
+    elf.apply_overrides(entry_override, gp_override, sp_override);
 
     let entry = elf.entry();
     let gp = elf.gp();
     let sp = elf.stack();
     let sections = elf.into_sections();
 
-    let psexe = psexe::PsxWriter::new(Path::new(psexepath), region);
+    if let Some(sympath) = sympath {
+        psexe::PsxWriter::dump_symbols(Path::new(sympath), &sections)?;
+    }
+
+    let psexe = psexe::PsxWriter::new(Path::new(psexepath), region)?;
 
-    psexe.dump(entry, sections, gp, sp);
+    psexe.dump(entry, sections, gp, sp)
 }
+
@@ -2,6 +2,8 @@ use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::path::Path;
 
+use elf;
+use Error;
 use Section;
 use SectionType;
 use Region;
@@ -12,43 +14,101 @@ pub struct PsxWriter {
 }
 
 impl PsxWriter {
-    pub fn new(path: &Path, region: Region) -> PsxWriter {
-        let psexe =
-            match OpenOptions::new()
-            .write(true).create(true).truncate(true).open(path) {
-                Ok(psexe) => psexe,
-                Err(e) => panic!("Can't open {}: {}", path.display(), e),
-            };
+    pub fn new(path: &Path, region: Region) -> Result<PsxWriter, Error> {
+        let psexe = OpenOptions::new()
+            .write(true).create(true).truncate(true).open(path)?;
 
-        PsxWriter {
+        Ok(PsxWriter {
             psexe: psexe,
             region: region,
+        })
+    }
+
+    /// Write a no$psx-style symbol map (address, kind, name) next to
+    /// the PSX-EXE, resolved from the ELF's Symtab/Strtab the same
+    /// way `nm`/`objdump` expose an ELF's own symbol table. Debuggers
+    /// and emulators can load this file to show function and data
+    /// labels while stepping through the EXE. Does nothing if the ELF
+    /// carried no symbol/string table.
+    pub fn dump_symbols(path: &Path, sections: &[Section]) -> Result<(), Error> {
+        let symtab = sections.iter().filter_map(|s| {
+            match &s.contents {
+                SectionType::Symtab(v) => Some(v),
+                _ => None,
+            }
+        }).next();
+
+        let strtab = sections.iter().filter_map(|s| {
+            match &s.contents {
+                SectionType::Strtab(v) => Some(v),
+                _ => None,
+            }
+        }).next();
+
+        let (symtab, strtab) = match (symtab, strtab) {
+            (Some(symtab), Some(strtab)) => (symtab, strtab),
+            _ => return Ok(()),
+        };
+
+        let mut symbols: Vec<(u32, char, &str)> = symtab.iter()
+            // SHN_UNDEF (no definition) and zero-value entries don't
+            // point anywhere useful.
+            .filter(|sym| sym.shndx != 0 && sym.value != 0)
+            .filter_map(|sym| {
+                // A corrupt symtab can claim an st_name past the end
+                // of the string table; skip the symbol instead of
+                // panicking on the slice index.
+                let name = elf::str_from_u8_nul_utf8(strtab.get(sym.name as usize..)?).ok()?;
+                if name.is_empty() {
+                    return None;
+                }
+
+                // STT_FUNC/STT_OBJECT, the low nibble of st_info.
+                let kind = match sym.info & 0xf {
+                    1 => 'D',
+                    2 => 'T',
+                    _ => return None,
+                };
+
+                Some((sym.value, kind, name))
+            })
+            .collect();
+
+        symbols.sort_by_key(|&(addr, _, _)| addr);
+
+        let mut file = OpenOptions::new()
+            .write(true).create(true).truncate(true).open(path)?;
+
+        for (addr, kind, name) in symbols {
+            writeln!(file, "{:08x} {} {}", addr, kind, name)?;
         }
+
+        Ok(())
     }
 
-    pub fn dump(mut self, entry: u32, mut sections: Vec<Section>, gp: u32) {
+    pub fn dump(mut self, entry: u32, mut sections: Vec<Section>, gp: u32, sp: u32) -> Result<(), Error> {
         // Magic
-        self.write(b"PS-X EXE");
+        self.write(b"PS-X EXE")?;
 
         // Padding
-        self.write(&[0; 8]);
+        self.write(&[0; 8])?;
 
         // First PC address (entry point)
         println!("Entry PC:       0x{:08x}", entry);
-        self.write32(entry);
+        self.write32(entry)?;
 
         // Initial GP
         println!("Initial GP:     0x{:08x}", gp);
-        self.write32(gp);
+        self.write32(gp)?;
 
         // Sort the sections by base address since that's how we're
         // going to dump them
         sections.sort_by(|s1, s2| s1.base.cmp(&s2.base));
 
         // Base address
-        let base = sections[0].base;
+        let base = sections.get(0).ok_or(Error::NoProgbits)?.base;
         println!("Base address:   0x{:08x}", base);
-        self.write32(base);
+        self.write32(base)?;
 
         // Object size (file size minus the 2048bytes header). Since
         // we've sorted the list by base address and sections
@@ -62,28 +122,24 @@ impl PsxWriter {
                     // and return that
                     SectionType::ProgBits(ref p) =>
                         Some(s.base + p.len() as u32),
-                    // We ignore memfill sections since they take no
-                    // space in the file
-                    SectionType::Memfill(_) => None,
+                    // We ignore any section that doesn't occupy space
+                    // in the file
+                    _ => None,
                 }
             })
             // We only care about the last section
             .last();
 
-        let end_addr =
-            match end_addr {
-                Some(e) => e,
-                _ => panic!("No progbits section found!"),
-            };
+        let end_addr = end_addr.ok_or(Error::NoProgbits)?;
 
         let actual_object_size = end_addr - base;
         // Arbitrarily refuse object files greater than 1MB. The PSX
         // only has 2MB of RAM, most executables are a few hundred KBs
         // at most.
         if actual_object_size > 1 * 1024 * 1024 {
-            panic!("Object is too big");
+            return Err(Error::ObjectTooBig(actual_object_size));
         }
-        
+
         let padded_object_size = if (actual_object_size % 2048) == 0 {
             actual_object_size
         } else {
@@ -94,11 +150,11 @@ impl PsxWriter {
             "Text+data size: {}B (actual {}B)",
             padded_object_size, actual_object_size
         );
-        self.write32(padded_object_size);
+        self.write32(padded_object_size)?;
 
         // I don't know what the two next words do but the Nocash spec
         // says that they're "usually 0"
-        self.write(&[0; 8]);
+        self.write(&[0; 8])?;
 
         // Next we want to initialize the memfill
         let memfill = sections.iter().filter_map(
@@ -109,37 +165,40 @@ impl PsxWriter {
                 }
             });
 
-        let (memfill_base, memfill_length) =
-            memfill.fold((0, 0), |(base, lensum), (secbase, seclen)| {
-                if base == 0 {
-                    (secbase, seclen)
-                } else if base+lensum == secbase {
-                    (base, lensum+seclen)
-                } else {
-                    panic!("Got discontiguous memfill sections!");
-                }
-            });
+        let mut memfill_base = 0;
+        let mut memfill_length = 0;
+        for (secbase, seclen) in memfill {
+            if memfill_base == 0 {
+                memfill_base = secbase;
+                memfill_length = seclen;
+            } else if memfill_base + memfill_length == secbase {
+                memfill_length += seclen;
+            } else {
+                return Err(Error::DiscontiguousMemfill);
+            }
+        }
 
         println!("Memfill base:   0x{:08x}", memfill_base);
-        self.write32(memfill_base);
+        self.write32(memfill_base)?;
         println!("Memfill length: {}B", memfill_length);
-        self.write32(memfill_length);
+        self.write32(memfill_length)?;
 
-        // For now hardcode SP base and offset.
-        let sp     = 0x801ffff0;
+        // SP base comes from a command line override, else from a
+        // __stack/__sp/_stack_top symbol, else defaults to
+        // 0x801ffff0; the offset into that region is always 0.
         let sp_off = 0;
 
         println!("SP base:        0x{:08x}", sp);
-        self.write32(sp);
+        self.write32(sp)?;
         println!("SP offset:      {}", sp_off);
-        self.write32(sp_off);
+        self.write32(sp_off)?;
 
         // Padding that is used by the BIOS to store R16, R28, R30, SP
         // and RA when it starts the execution of our program.
-        self.write(&[0; 20]);
+        self.write(&[0; 20])?;
 
         // License marker.
-        self.write(b"Sony Computer Entertainment Inc. for ");
+        self.write(b"Sony Computer Entertainment Inc. for ")?;
 
         let region_str =
             match self.region {
@@ -149,12 +208,12 @@ impl PsxWriter {
             };
 
         println!("Region:         {}", region_str);
-        self.write(region_str.as_bytes());
+        self.write(region_str.as_bytes())?;
 
         // *huge* pad before we reach the actual object. Not sure why
         // they did that...
         let pad = vec![0; 1935 - region_str.len()];
-        self.write(&pad);
+        self.write(&pad)?;
 
         // Finally we can dump the progbits sections
         let progbits = sections.iter().filter_map(
@@ -172,35 +231,34 @@ impl PsxWriter {
             // one we fill it with 0s
             let padlen = base - offset;
             let pad = vec![0; padlen as usize];
-            self.write(&pad);
+            self.write(&pad)?;
 
             // And we can dump the data
-            self.write(data);
+            self.write(data)?;
 
             // Update the offset
             offset = base + data.len() as u32;
         }
-        
+
         let endpad = vec![0; (padded_object_size - actual_object_size) as usize];
-        self.write(&endpad);
+        self.write(&endpad)?;
+
+        Ok(())
     }
 
-    fn write(&mut self, v: &[u8]) {
-        match self.psexe.write(v) {
-            Ok(n) => {
-                if n != v.len() {
-                    panic!("Couldn't write {} bytes to file", v.len());
-                }
-            }
-            Err(e) => panic!("Write failed: {}", e),
+    fn write(&mut self, v: &[u8]) -> Result<(), Error> {
+        let n = self.psexe.write(v)?;
+        if n != v.len() {
+            return Err(Error::UnexpectedEof);
         }
+        Ok(())
     }
 
     /// Write 32bit value in the file in little endian
-    fn write32(&mut self, v: u32) {
+    fn write32(&mut self, v: u32) -> Result<(), Error> {
         self.write(&[ v as u8,
                       (v >> 8) as u8,
                       (v >> 16) as u8,
-                      (v >> 24) as u8]);
+                      (v >> 24) as u8])
     }
 }